@@ -1,45 +1,258 @@
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use log::{debug, error, info};
 use std::cmp;
-use std::fs::{create_dir_all, read_link, File};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, create_dir_all, read_link, File};
 use std::io::ErrorKind as IOKind;
-use std::os::unix::fs::symlink;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread;
 use walkdir::{DirEntry, WalkDir};
 
 use crate::errors::{io_err, Result, XcpError};
 use crate::os::copy_file_bytes;
 use crate::progress::{
-    iprogress_bar, BatchUpdater, NopUpdater, ProgressBar, ProgressUpdater, StatusUpdate, Updater,
-    BATCH_DEFAULT,
+    iprogress_bar, BatchUpdater, NopUpdater, ProgressBar, StatusUpdate, Updater, BATCH_DEFAULT,
 };
 use crate::utils::{FileType, ToFileType};
 use crate::Opts;
 
 
+/// Which `mknod` device class to recreate; carried alongside the raw
+/// `rdev` major/minor pair from the source node's metadata.
+#[derive(Debug, Clone, Copy)]
+enum DeviceKind {
+    Char,
+    Block,
+}
+
+/// `--backup[=CONTROL]` policy for displacing a file that a copy is
+/// about to overwrite, mirroring GNU `cp`'s backup control values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackupMode {
+    /// Always append a single `~`.
+    Simple,
+    /// Always use `file.~N~`, with `N` one past the highest existing one.
+    Numbered,
+    /// Numbered if numbered backups already exist for this file, else simple.
+    Existing,
+}
+
 #[derive(Debug)]
 enum Operation {
     Copy(PathBuf, PathBuf),
     Link(PathBuf, PathBuf),
+    Hardlink(PathBuf, PathBuf),
+    Fifo(PathBuf),
+    Device(PathBuf, DeviceKind, u64),
     CreateDir(PathBuf),
     End,
 }
 
 
-fn copy_file(from: &Path, to: &Path, updates: &mut BatchUpdater) -> Result<u64> {
+fn path_to_cstring(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io_err(IOKind::InvalidInput, "path contains a NUL byte").into())
+}
+
+fn mkfifo(path: &Path) -> Result<()> {
+    let cpath = path_to_cstring(path)?;
+    let ret = unsafe { libc::mkfifo(cpath.as_ptr(), 0o644) };
+    if ret != 0 {
+        return Err(io_err(IOKind::Other, "mkfifo failed").into());
+    }
+    Ok(())
+}
+
+fn mknod_device(path: &Path, kind: DeviceKind, rdev: u64) -> Result<()> {
+    let cpath = path_to_cstring(path)?;
+    let mode = match kind {
+        DeviceKind::Char => libc::S_IFCHR | 0o600,
+        DeviceKind::Block => libc::S_IFBLK | 0o600,
+    };
+    let ret = unsafe { libc::mknod(cpath.as_ptr(), mode, rdev as libc::dev_t) };
+    if ret != 0 {
+        return Err(io_err(IOKind::Other, "mknod failed").into());
+    }
+    Ok(())
+}
+
+/// Counter used to keep concurrent `--atomic` temp-file names unique
+/// within a single process.
+static ATOMIC_TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Build a sibling temp path for `to`, in the same directory (so the
+/// final `fs::rename` stays on the same filesystem and is atomic).
+fn atomic_tmp_path(to: &Path) -> PathBuf {
+    let dir = to.parent().unwrap_or_else(|| Path::new("."));
+    let name = to
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let unique = ATOMIC_TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    dir.join(format!(".{}.xcp-{}-{}", name, std::process::id(), unique))
+}
+
+fn simple_backup_path(to: &Path) -> PathBuf {
+    let mut name = to.as_os_str().to_os_string();
+    name.push("~");
+    PathBuf::from(name)
+}
+
+fn numbered_backup_path(to: &Path) -> PathBuf {
+    let name = to.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let prefix = format!("{}.~", name);
+    let parent = to.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let highest = fs::read_dir(parent)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let fname = e.file_name().into_string().ok()?;
+            let n = fname.strip_prefix(prefix.as_str())?.strip_suffix('~')?;
+            n.chars().all(|c| c.is_ascii_digit()).then(|| n.parse::<u32>().ok()).flatten()
+        })
+        .max()
+        .unwrap_or(0);
+    PathBuf::from(format!("{}.~{}~", to.display(), highest + 1))
+}
+
+/// Whether any numbered backup (`<name>.~N~` for some `N`) already
+/// exists for `to`, regardless of which `N`s are actually present -
+/// `--backup=existing` should keep extending the same numbered series
+/// even if e.g. `.~1~` was since deleted but `.~2~` remains.
+fn has_numbered_backup(to: &Path) -> bool {
+    let parent = match to.parent() {
+        Some(p) if p.as_os_str().is_empty() => Path::new("."),
+        Some(p) => p,
+        None => return false,
+    };
+    let name = match to.file_name().and_then(|n| n.to_str()) {
+        Some(n) => n,
+        None => return false,
+    };
+    let prefix = format!("{}.~", name);
+
+    fs::read_dir(parent)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .any(|e| {
+            e.file_name()
+                .to_str()
+                .and_then(|fname| fname.strip_prefix(prefix.as_str()))
+                .and_then(|rest| rest.strip_suffix('~'))
+                .map_or(false, |n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+        })
+}
+
+/// Move `to` aside per `mode` if it exists, so the caller is then free
+/// to create a fresh file at `to` without losing the original.
+fn backup_existing(to: &Path, mode: BackupMode) -> Result<()> {
+    if !to.exists() {
+        return Ok(());
+    }
+    let backup_path = match mode {
+        BackupMode::Simple => simple_backup_path(to),
+        BackupMode::Numbered => numbered_backup_path(to),
+        BackupMode::Existing if has_numbered_backup(to) => numbered_backup_path(to),
+        BackupMode::Existing => simple_backup_path(to),
+    };
+    debug!("Backing up {:?} to {:?}", to, backup_path);
+    fs::rename(to, backup_path)?;
+    Ok(())
+}
+
+fn copy_file(
+    from: &Path,
+    to: &Path,
+    atomic: bool,
+    update_only: bool,
+    backup: Option<BackupMode>,
+    updates: &mut BatchUpdater,
+) -> Result<u64> {
     let infd = File::open(from)?;
-    let outfd = File::create(to)?;
-    let (perm, len) = {
-        let metadata = infd.metadata()?;
-        (metadata.permissions(), metadata.len())
+    let meta_in = infd.metadata()?;
+
+    if update_only && to.exists() {
+        let to_meta = to.metadata()?;
+        if to_meta.modified()? >= meta_in.modified()? {
+            debug!(
+                "Skipping {:?}; destination is not older than source (--update)",
+                to
+            );
+            updates.update(Ok(meta_in.len()))?;
+            return Ok(0);
+        }
+    }
+
+    let (perm, len) = (meta_in.permissions(), meta_in.len());
+    let write_path = if atomic { atomic_tmp_path(to) } else { to.to_path_buf() };
+
+    if !atomic {
+        // Non-atomic writes truncate `to` via `File::create`, so any
+        // backup has to happen first.
+        if let Some(mode) = backup {
+            backup_existing(to, mode)?;
+        }
+    }
+
+    let result = write_file_contents(&infd, &write_path, len, perm, updates);
+
+    let written = match result {
+        Ok(written) => written,
+        Err(e) => {
+            // Don't leave `--atomic` debris behind on a failed copy.
+            if atomic {
+                let _ = fs::remove_file(&write_path);
+            }
+            return Err(e);
+        }
+    };
+
+    if atomic {
+        // The temp file is fully written; only now do we touch `to` -
+        // back up the existing file, then swap the new one into place
+        // with a single rename, so a crash or interrupted copy never
+        // leaves a truncated file at the destination.
+        if let Err(e) = atomic_finish(&write_path, to, backup) {
+            let _ = fs::remove_file(&write_path);
+            return Err(e);
+        }
+    }
+
+    Ok(written)
+}
+
+/// Create `write_path` (creating its parent directory first if that's
+/// why `File::create` failed) and stream `len` bytes from `infd` into
+/// it, applying `perm` once the data is fully written.
+fn write_file_contents(
+    infd: &File,
+    write_path: &Path,
+    len: u64,
+    perm: fs::Permissions,
+    updates: &mut BatchUpdater,
+) -> Result<u64> {
+    let outfd = match File::create(write_path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == IOKind::NotFound => {
+            if let Some(parent) = write_path.parent() {
+                create_dir_all(parent)?;
+            }
+            File::create(write_path)?
+        }
+        Err(e) => return Err(e.into()),
     };
 
     let mut written = 0u64;
     while written < len {
         let bytes_to_copy = cmp::min(len - written, updates.batch_size);
-        let result = copy_file_bytes(&infd, &outfd, bytes_to_copy)?;
+        let result = copy_file_bytes(infd, &outfd, bytes_to_copy)?;
         written += result;
         updates.update(Ok(result))?;
     }
@@ -47,20 +260,97 @@ fn copy_file(from: &Path, to: &Path, updates: &mut BatchUpdater) -> Result<u64>
     Ok(written)
 }
 
+/// Back up the existing destination (if requested) and swap the
+/// fully-written temp file into place with a single rename.
+fn atomic_finish(write_path: &Path, to: &Path, backup: Option<BackupMode>) -> Result<()> {
+    if let Some(mode) = backup {
+        backup_existing(to, mode)?;
+    }
+    fs::rename(write_path, to)?;
+    Ok(())
+}
+
 
-fn copy_worker(work: mpsc::Receiver<Operation>, mut updates: BatchUpdater) -> Result<()> {
+/// Tracks, per destination path, whether the `Operation::Copy` that
+/// writes it has finished and whether it succeeded. A same-inode
+/// `Operation::Hardlink` queued behind that copy waits on this instead
+/// of racing `fs::hard_link` against a still-in-progress write.
+#[derive(Default)]
+struct CopyCompletions {
+    done: Mutex<HashMap<PathBuf, bool>>,
+    cv: Condvar,
+}
+
+impl CopyCompletions {
+    /// Record that the copy to `path` finished, with `ok` indicating
+    /// success, and wake any hardlinks waiting on it.
+    fn mark(&self, path: &Path, ok: bool) {
+        let mut done = self.done.lock().expect("copy-completions lock poisoned");
+        done.insert(path.to_path_buf(), ok);
+        self.cv.notify_all();
+    }
+
+    /// Block until the copy to `path` has been marked, returning
+    /// whether it succeeded.
+    fn wait_for(&self, path: &Path) -> bool {
+        let mut done = self.done.lock().expect("copy-completions lock poisoned");
+        loop {
+            if let Some(ok) = done.get(path) {
+                return *ok;
+            }
+            done = self.cv.wait(done).expect("copy-completions lock poisoned");
+        }
+    }
+}
+
+/// Default number of copy workers when `-j`/`--workers` is not given.
+/// Used as the `clap` default for `Opts::workers`.
+pub(crate) fn default_workers() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// Run one worker of the copy-worker pool, pulling operations off the
+/// shared `work` queue until it sees `Operation::End` or the channel
+/// closes. `tree_walker` guarantees that a file's parent directory is
+/// created before its `Operation::Copy` is enqueued, so it is safe for
+/// several of these to run concurrently without serializing on
+/// directory creation.
+///
+/// A file's first occurrence is always sent as `Operation::Copy`, and
+/// every later path sharing its inode is sent as `Operation::Hardlink`
+/// against that first occurrence's destination. Since these can land on
+/// different workers, `completions` lets the `Hardlink` side block until
+/// the matching `Copy` has actually finished, instead of racing it.
+fn copy_worker(
+    work: Arc<Mutex<mpsc::Receiver<Operation>>>,
+    atomic: bool,
+    update_only: bool,
+    backup: Option<BackupMode>,
+    completions: Arc<CopyCompletions>,
+    mut updates: BatchUpdater,
+) -> Result<()> {
     debug!("Starting copy worker {:?}", thread::current().id());
-    for op in work {
+    loop {
+        let op = {
+            let rx = work.lock().expect("copy-worker queue lock poisoned");
+            rx.recv()
+        };
+        let op = match op {
+            Ok(op) => op,
+            Err(_) => break,
+        };
         debug!("Received operation {:?}", op);
 
-        // FIXME: If we implement parallel copies (which may
-        // improve performance on some SSD configurations) we
-        // should also created the parent directory, and the
-        // dir-create operation could be out of order.
         match op {
             Operation::Copy(from, to) => {
                 info!("Worker: Copy {:?} -> {:?}", from, to);
-                let _res = copy_file(&from, &to, &mut updates);
+                let res = copy_file(&from, &to, atomic, update_only, backup, &mut updates);
+                if let Err(ref e) = res {
+                    error!("Failed to copy {:?} -> {:?}: {}", from, to, e);
+                }
+                completions.mark(&to, res.is_ok());
             }
 
             Operation::Link(from, to) => {
@@ -68,6 +358,34 @@ fn copy_worker(work: mpsc::Receiver<Operation>, mut updates: BatchUpdater) -> Re
                 let _res = symlink(&from, &to);
             }
 
+            Operation::Hardlink(existing, to) => {
+                info!("Worker: Hardlink {:?} -> {:?}", existing, to);
+                if completions.wait_for(&existing) {
+                    if let Err(e) = fs::hard_link(&existing, &to) {
+                        error!("Failed to hardlink {:?} -> {:?}: {}", existing, to, e);
+                    }
+                } else {
+                    error!(
+                        "Skipping hardlink {:?} -> {:?}: original copy to {:?} failed",
+                        existing, to, existing
+                    );
+                }
+            }
+
+            Operation::Fifo(path) => {
+                info!("Worker: mkfifo {:?}", path);
+                if let Err(e) = mkfifo(&path) {
+                    error!("Failed to create FIFO {:?}: {}", path, e);
+                }
+            }
+
+            Operation::Device(path, kind, rdev) => {
+                info!("Worker: mknod {:?} ({:?})", path, kind);
+                if let Err(e) = mknod_device(&path, kind, rdev) {
+                    error!("Failed to create {:?} device {:?}: {}", kind, path, e);
+                }
+            }
+
             Operation::CreateDir(dir) => {
                 info!("Worker: Creating directory: {:?}", dir);
                 create_dir_all(&dir)?;
@@ -85,51 +403,129 @@ fn copy_worker(work: mpsc::Receiver<Operation>, mut updates: BatchUpdater) -> Re
 }
 
 
-fn ignore_filter(entry: &DirEntry, ignore: &Option<Gitignore>) -> bool {
-    match ignore {
-        None => true,
-        Some(gi) => {
-            let path = entry.path();
-            let m = gi.matched(&path, path.is_dir());
-            !m.is_ignore()
+/// One level of the hierarchical gitignore stack: a matcher together
+/// with the `WalkDir` depth of the directory it is rooted at (so it
+/// only applies to that directory's descendants). `ROOT_DEPTH` marks a
+/// matcher that applies to the whole walk and is never popped.
+struct GitignoreLevel {
+    depth: usize,
+    matcher: Gitignore,
+}
+
+/// Sentinel `GitignoreLevel::depth` for matchers seeded once before the
+/// walk starts (global gitignore, `.git/info/exclude`, root
+/// `.gitignore`). These are rooted "above" the source directory itself,
+/// so they must never be popped by backtracking out of a subtree - the
+/// walk can never backtrack past its own root.
+const ROOT_DEPTH: usize = usize::MAX;
+
+/// Test `entry` against the gitignore stack, pushing a new matcher
+/// when `entry` is a directory containing its own `.gitignore`, and
+/// popping matchers for directories the walk has backtracked out of.
+/// Deeper matchers are consulted first, so a nested `.gitignore` can
+/// override a shallower one, matching Git's own precedence.
+fn ignore_filter(entry: &DirEntry, enabled: bool, stack: &mut Vec<GitignoreLevel>) -> bool {
+    if !enabled {
+        return true;
+    }
+
+    while stack
+        .last()
+        .map_or(false, |lvl| lvl.depth != ROOT_DEPTH && entry.depth() <= lvl.depth)
+    {
+        stack.pop();
+    }
+
+    let path = entry.path();
+    let ignored = stack.iter().rev().find_map(|lvl| {
+        let m = lvl.matcher.matched(path, path.is_dir());
+        if m.is_ignore() {
+            Some(true)
+        } else if m.is_whitelist() {
+            Some(false)
+        } else {
+            None
+        }
+    }).unwrap_or(false);
+
+    if !ignored && entry.file_type().is_dir() {
+        let gi_file = path.join(".gitignore");
+        if gi_file.exists() {
+            let mut builder = GitignoreBuilder::new(path);
+            builder.add(&gi_file);
+            if let Ok(matcher) = builder.build() {
+                stack.push(GitignoreLevel { depth: entry.depth(), matcher });
+            }
         }
     }
+
+    !ignored
 }
 
 fn empty(path: &Path) -> bool {
     *path == PathBuf::new()
 }
 
+/// Build the gitignore matchers that apply across the whole of
+/// `source`: the user's global gitignore (if enabled), the repo's
+/// `.git/info/exclude`, and the root `.gitignore`. Seeded at
+/// `ROOT_DEPTH` so `ignore_filter`'s backtrack-pop never retires them.
+/// Shared by `tree_walker` and `verify_copy` so `--verify` agrees with
+/// the copy about what `--gitignore` excluded.
+fn seed_gitignore_stack(source: &Path, opts: &Opts) -> Vec<GitignoreLevel> {
+    let mut stack = Vec::new();
+    if !opts.gitignore {
+        return stack;
+    }
+
+    if opts.gitignore_global {
+        let (global_matcher, err) = Gitignore::global();
+        if let Some(e) = err {
+            debug!("Error loading global gitignore: {}", e);
+        }
+        stack.push(GitignoreLevel { depth: ROOT_DEPTH, matcher: global_matcher });
+    }
+
+    let exclude_file = source.join(".git/info/exclude");
+    if exclude_file.exists() {
+        let mut builder = GitignoreBuilder::new(source);
+        builder.add(&exclude_file);
+        if let Ok(matcher) = builder.build() {
+            stack.push(GitignoreLevel { depth: ROOT_DEPTH, matcher });
+        }
+    }
+
+    let root_gitignore = source.join(".gitignore");
+    if root_gitignore.exists() {
+        let mut builder = GitignoreBuilder::new(source);
+        builder.add(&root_gitignore);
+        if let Ok(matcher) = builder.build() {
+            stack.push(GitignoreLevel { depth: ROOT_DEPTH, matcher });
+        }
+    }
+
+    stack
+}
+
 fn tree_walker(
     source: PathBuf,
     opts: Opts,
+    target_base: PathBuf,
     work_tx: mpsc::Sender<Operation>,
     mut updates: BatchUpdater,
 ) -> Result<()> {
     debug!("Starting walk worker {:?}", thread::current().id());
-
-    let sourcedir = source.components().last().ok_or(XcpError::InvalidSource {
-        msg: "Failed to find source directory name.",
-    })?;
-
-    let target_base = if opts.dest.exists() {
-        opts.dest.join(sourcedir)
-    } else {
-        opts.dest.clone()
-    };
     debug!("Target base is {:?}", target_base);
 
-    let gitignore = if opts.gitignore {
-        let mut builder = GitignoreBuilder::new(&source);
-        builder.add(&source.join(".gitignore"));
-        let ignore = builder.build()?;
-        Some(ignore)
-    } else {
-        None
-    };
+    let mut gitignore_stack = seed_gitignore_stack(&source, &opts);
+
+    // Tracks (dev, inode) -> already-created destination path, so that
+    // later entries sharing an inode with an earlier one are hardlinked
+    // rather than copied again.
+    let mut inodes: HashMap<(u64, u64), PathBuf> = HashMap::new();
 
     for entry in WalkDir::new(&source).into_iter()
-        .filter_entry(|e| ignore_filter(e, &gitignore))
+        .filter_entry(|e| ignore_filter(e, opts.gitignore, &mut gitignore_stack))
     {
         debug!("Got tree entry {:?}", entry);
         let e = entry?;
@@ -143,7 +539,9 @@ fn tree_walker(
         };
 
         if target.exists() && opts.noclobber {
-            work_tx.send(Operation::End)?;
+            for _ in 0..opts.workers.max(1) {
+                work_tx.send(Operation::End)?;
+            }
             updates.update(Err(XcpError::DestinationExists {
                 msg: "Destination file exists and --no-clobber is set.",
                 path: target }.into()))?;
@@ -153,8 +551,28 @@ fn tree_walker(
             .into());
         }
 
+        // If this entry shares inode with one we've already placed at
+        // the destination, hardlink rather than copy the data again.
+        if opts.preserve_links && meta.is_file() && meta.nlink() > 1 {
+            let inode_key = (meta.dev(), meta.ino());
+            if let Some(existing) = inodes.get(&inode_key) {
+                debug!("Send hardlink operation {:?} -> {:?}", existing, target);
+                work_tx.send(Operation::Hardlink(existing.clone(), target))?;
+                continue;
+            }
+            inodes.insert(inode_key, target.clone());
+        }
+
         match meta.file_type().to_enum() {
             FileType::File => {
+                // The copy-worker pool may pick this operation up on any
+                // worker thread, racing the CreateDir operation for its
+                // parent; create the parent here, synchronously in the
+                // walker, so ordering is guaranteed regardless of which
+                // worker drains it.
+                if let Some(parent) = target.parent() {
+                    create_dir_all(parent)?;
+                }
                 debug!("Send copy operation {:?} to {:?}", from, target);
                 updates.update(Ok(meta.len()))?;
                 work_tx.send(Operation::Copy(from, target))?;
@@ -172,74 +590,138 @@ fn tree_walker(
                 updates.update(Ok(from.metadata()?.len()))?;
             }
 
+            FileType::Fifo => {
+                debug!("Send mkfifo operation for {:?}", target);
+                if let Some(parent) = target.parent() {
+                    create_dir_all(parent)?;
+                }
+                work_tx.send(Operation::Fifo(target))?;
+            }
+
+            FileType::CharDevice => {
+                debug!("Send mknod (char) operation for {:?}", target);
+                if let Some(parent) = target.parent() {
+                    create_dir_all(parent)?;
+                }
+                work_tx.send(Operation::Device(target, DeviceKind::Char, meta.rdev()))?;
+            }
+
+            FileType::BlockDevice => {
+                debug!("Send mknod (block) operation for {:?}", target);
+                if let Some(parent) = target.parent() {
+                    create_dir_all(parent)?;
+                }
+                work_tx.send(Operation::Device(target, DeviceKind::Block, meta.rdev()))?;
+            }
+
+            FileType::Socket => {
+                info!("Skipping socket {:?}; sockets cannot be meaningfully copied", from);
+            }
+
             FileType::Unknown => {
                 error!("Unknown filetype found; this should never happen!");
-                work_tx.send(Operation::End)?;
+                for _ in 0..opts.workers.max(1) {
+                    work_tx.send(Operation::End)?;
+                }
                 updates.update(Err(XcpError::UnknownFiletype { path: target }.into()))?;
             }
         };
     }
 
-    work_tx.send(Operation::End)?;
+    for _ in 0..opts.workers.max(1) {
+        work_tx.send(Operation::End)?;
+    }
     debug!("Walk-worker finished: {:?}", thread::current().id());
     Ok(())
 }
 
-pub fn copy_tree(source: PathBuf, opts: &Opts) -> Result<()> {
-    let (work_tx, work_rx) = mpsc::channel();
-    let (stat_tx, stat_rx) = mpsc::channel();
+pub fn copy_tree(
+    source: PathBuf,
+    opts: &Opts,
+    pb: &ProgressBar,
+    total_bytes: &Arc<AtomicU64>,
+    copied_bytes: &Arc<AtomicU64>,
+) -> Result<()> {
+    let verify_source = source.clone();
 
-    let (pb, batch_size) = if opts.noprogress {
-        (ProgressBar::Nop, usize::max_value() as u64)
+    // Computed once, up front, and reused for both the walk and the
+    // post-copy verify: re-deriving it a second time after the copy has
+    // run would see `opts.dest` in its post-copy state (e.g. now
+    // existing when it didn't before), giving the wrong answer.
+    let sourcedir = verify_source.components().last().ok_or(XcpError::InvalidSource {
+        msg: "Failed to find source directory name.",
+    })?;
+    let target_base = if !opts.no_target_directory && opts.dest.exists() {
+        opts.dest.join(sourcedir)
     } else {
-        (iprogress_bar(0), BATCH_DEFAULT)
+        opts.dest.clone()
     };
 
+    let (work_tx, work_rx) = mpsc::channel();
+    let (stat_tx, stat_rx) = mpsc::channel();
 
-    let _copy_worker = {
-        let copy_stat = BatchUpdater {
-            sender: Box::new(stat_tx.clone()),
-            stat: StatusUpdate::Copied(0),
-            batch_size: batch_size,
-        };
-        thread::spawn(move || copy_worker(work_rx, copy_stat))
-    };
+    let batch_size = if opts.noprogress { usize::max_value() as u64 } else { BATCH_DEFAULT };
+
+    let work_rx = Arc::new(Mutex::new(work_rx));
+    let completions = Arc::new(CopyCompletions::default());
+    let _copy_workers: Vec<_> = (0..opts.workers.max(1))
+        .map(|_| {
+            let work_rx = Arc::clone(&work_rx);
+            let completions = Arc::clone(&completions);
+            let (atomic, update, backup) = (opts.atomic, opts.update, opts.backup);
+            let copy_stat = BatchUpdater {
+                sender: Box::new(stat_tx.clone()),
+                stat: StatusUpdate::Copied(0),
+                batch_size: batch_size,
+            };
+            thread::spawn(move || {
+                copy_worker(work_rx, atomic, update, backup, completions, copy_stat)
+            })
+        })
+        .collect();
     let _walk_worker = {
         let topts = opts.clone();
+        let walk_target_base = target_base.clone();
         let size_stat = BatchUpdater {
             sender: Box::new(stat_tx),
             stat: StatusUpdate::Size(0),
             batch_size: batch_size,
         };
-        thread::spawn(move || tree_walker(source, topts, work_tx, size_stat))
+        thread::spawn(move || tree_walker(source, topts, walk_target_base, work_tx, size_stat))
     };
 
-    let mut copied = 0;
-    let mut total = 0;
-
     for stat in stat_rx {
         match stat? {
             StatusUpdate::Size(s) => {
-                total += s;
+                let total = total_bytes.fetch_add(s, Ordering::Relaxed) + s;
                 pb.set_size(total);
             }
             StatusUpdate::Copied(s) => {
-                copied += s;
+                let copied = copied_bytes.fetch_add(s, Ordering::Relaxed) + s;
                 pb.set_position(copied);
             }
         }
     }
     // FIXME: We should probably join the threads and consume any errors.
 
-    pb.end();
     debug!("Copy-tree complete");
 
+    if opts.verify {
+        verify_copy(&verify_source, &target_base, opts)?;
+    }
+
     Ok(())
 }
 
 
-pub fn copy_single_file(source: &PathBuf, opts: &Opts) -> Result<()> {
-    let dest = if opts.dest.is_dir() {
+pub fn copy_single_file(
+    source: &PathBuf,
+    opts: &Opts,
+    pb: &ProgressBar,
+    total_bytes: &Arc<AtomicU64>,
+    copied_bytes: &Arc<AtomicU64>,
+) -> Result<()> {
+    let dest = if !opts.no_target_directory && opts.dest.is_dir() {
         let fname = source.file_name().ok_or(XcpError::UnknownFilename)?;
         opts.dest.join(fname)
     } else {
@@ -262,17 +744,253 @@ pub fn copy_single_file(source: &PathBuf, opts: &Opts) -> Result<()> {
         }
     } else {
         let size = source.metadata()?.len();
+        let total = total_bytes.fetch_add(size, Ordering::Relaxed) + size;
+        pb.set_size(total);
         BatchUpdater {
-            sender: Box::new(ProgressUpdater {
-                pb: iprogress_bar(size),
-                written: 0,
+            sender: Box::new(SharedProgress {
+                pb: pb.clone(),
+                copied: Arc::clone(copied_bytes),
             }),
             stat: StatusUpdate::Copied(0),
             batch_size: BATCH_DEFAULT,
         }
     };
 
-    copy_file(source, &dest, &mut copy_stat)?;
+    copy_file(source, &dest, opts.atomic, opts.update, opts.backup, &mut copy_stat)?;
+
+    if opts.verify {
+        verify_entry(source, &dest).map_err(|reason| {
+            XcpError::VerifyFailed {
+                msg: format!("{:?}: {}", dest, reason),
+            }
+            .into()
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Hash a file's contents without loading it into memory.
+fn hash_file(path: &Path) -> Result<blake3::Hash> {
+    let mut file = File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(&mut file)?;
+    Ok(hasher.finalize())
+}
+
+/// Verify that `to` faithfully mirrors `from`: for regular files,
+/// matching size and content hash; for symlinks, a matching target;
+/// for directories and special files, a matching type. Returns a
+/// human-readable mismatch reason on failure.
+fn verify_entry(from: &Path, to: &Path) -> std::result::Result<(), String> {
+    let smeta = from.symlink_metadata().map_err(|e| e.to_string())?;
+    let dmeta = match to.symlink_metadata() {
+        Ok(m) => m,
+        Err(_) => return Err("missing at destination".to_string()),
+    };
+
+    match smeta.file_type().to_enum() {
+        FileType::File => {
+            if smeta.len() != dmeta.len() {
+                return Err(format!(
+                    "size mismatch: {} (source) vs {} (dest)",
+                    smeta.len(),
+                    dmeta.len()
+                ));
+            }
+            let shash = hash_file(from).map_err(|e| e.to_string())?;
+            let dhash = hash_file(to).map_err(|e| e.to_string())?;
+            if shash != dhash {
+                return Err("content hash mismatch".to_string());
+            }
+        }
+
+        FileType::Symlink => {
+            let slink = read_link(from).map_err(|e| e.to_string())?;
+            let dlink = read_link(to).map_err(|e| e.to_string())?;
+            if slink != dlink {
+                return Err(format!(
+                    "symlink target mismatch: {:?} (source) vs {:?} (dest)",
+                    slink, dlink
+                ));
+            }
+        }
+
+        FileType::Dir => {
+            if !dmeta.is_dir() {
+                return Err("expected a directory".to_string());
+            }
+        }
+
+        FileType::Fifo => {
+            if !dmeta.file_type().is_fifo() {
+                return Err("expected a FIFO".to_string());
+            }
+        }
+
+        FileType::CharDevice => {
+            if !dmeta.file_type().is_char_device() {
+                return Err("expected a character device".to_string());
+            }
+        }
+
+        FileType::BlockDevice => {
+            if !dmeta.file_type().is_block_device() {
+                return Err("expected a block device".to_string());
+            }
+        }
+
+        // Sockets aren't recreated by the copy, so there's nothing to
+        // check; truly unknown types were already rejected at copy time.
+        FileType::Socket | FileType::Unknown => {}
+    }
+
+    Ok(())
+}
+
+/// Walk `source` and verify every entry has a faithful counterpart
+/// under `dest`, collecting every mismatch before reporting rather
+/// than stopping at the first one. Applies the same `--gitignore`
+/// filtering as the copy itself, so entries the copy legitimately
+/// skipped aren't reported as missing.
+pub fn verify_copy(source: &Path, dest: &Path, opts: &Opts) -> Result<()> {
+    let mut mismatches: Vec<String> = Vec::new();
+    let mut gitignore_stack = seed_gitignore_stack(source, opts);
+
+    for entry in
+        WalkDir::new(source).into_iter().filter_entry(|e| ignore_filter(e, opts.gitignore, &mut gitignore_stack))
+    {
+        let e = entry?;
+        let from = e.path();
+        let rel = from.strip_prefix(source)?;
+        let to = if empty(rel) { dest.to_path_buf() } else { dest.join(rel) };
+
+        if let Err(reason) = verify_entry(from, &to) {
+            mismatches.push(format!("{:?}: {}", to, reason));
+        }
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(XcpError::VerifyFailed {
+            msg: format!(
+                "Verification failed for {} path(s):\n{}",
+                mismatches.len(),
+                mismatches.join("\n")
+            ),
+        }
+        .into())
+    }
+}
+
+/// Resolve `opts.sources`/`opts.dest`/`-t`/`-T` into a concrete
+/// `(source, destination)` pair per source, coreutils-`cp`-style:
+/// `-T` takes a single source and treats `dest` as the literal final
+/// path; `-t DIR` (or a bare `DEST` when there's more than one source)
+/// requires an existing directory that each source is copied into. A
+/// single source with a bare `DEST` that is an existing directory is
+/// copied into it too; otherwise `DEST` is the literal final path.
+fn resolve_copy_targets(opts: &Opts) -> Result<Vec<(PathBuf, PathBuf)>> {
+    if opts.no_target_directory {
+        if opts.sources.len() != 1 {
+            return Err(XcpError::InvalidDestination {
+                msg: "--no-target-directory requires exactly one SOURCE",
+            }
+            .into());
+        }
+        return Ok(vec![(opts.sources[0].clone(), opts.dest.clone())]);
+    }
+
+    let target_dir = if let Some(dir) = &opts.target_directory {
+        if !dir.is_dir() {
+            return Err(XcpError::InvalidDestination {
+                msg: "--target-directory must be an existing directory",
+            }
+            .into());
+        }
+        Some(dir.clone())
+    } else if opts.sources.len() > 1 {
+        if !opts.dest.is_dir() {
+            return Err(XcpError::InvalidDestination {
+                msg: "DEST must be an existing directory when copying multiple sources",
+            }
+            .into());
+        }
+        Some(opts.dest.clone())
+    } else if opts.dest.is_dir() {
+        Some(opts.dest.clone())
+    } else {
+        None
+    };
+
+    opts.sources
+        .iter()
+        .map(|source| {
+            let dest = match &target_dir {
+                Some(dir) => {
+                    let name = source.file_name().ok_or(XcpError::UnknownFilename)?;
+                    dir.join(name)
+                }
+                None => opts.dest.clone(),
+            };
+            Ok((source.clone(), dest))
+        })
+        .collect()
+}
+
+/// Forwards a single source's copied-byte updates into a `ProgressBar`
+/// shared across every source `copy_all` dispatches, via a running
+/// total, so multiple `SOURCE`s advance one combined bar instead of
+/// each getting its own.
+struct SharedProgress {
+    pb: ProgressBar,
+    copied: Arc<AtomicU64>,
+}
+
+impl Updater for SharedProgress {
+    fn update(&mut self, update: Result<u64>) -> Result<()> {
+        let n = update?;
+        let total = self.copied.fetch_add(n, Ordering::Relaxed) + n;
+        self.pb.set_position(total);
+        Ok(())
+    }
+}
+
+/// Copy every source in `opts.sources` to its resolved destination,
+/// dispatching each through the existing single-file/tree copy paths.
+/// All sources share one `ProgressBar` and one running size/copied
+/// total, so progress reflects the whole operation rather than
+/// resetting per source; this just adds the coreutils-style multi-
+/// `SOURCE` dispatch on top.
+pub fn copy_all(opts: &Opts) -> Result<()> {
+    let targets = resolve_copy_targets(opts)?;
+
+    let pb = if opts.noprogress { ProgressBar::Nop } else { iprogress_bar(0) };
+    let total_bytes = Arc::new(AtomicU64::new(0));
+    let copied_bytes = Arc::new(AtomicU64::new(0));
+
+    for (source, dest) in targets {
+        let mut source_opts = opts.clone();
+        source_opts.dest = dest;
+        // `resolve_copy_targets` has already turned `dest` into the
+        // exact final path for this source (joining on a target
+        // directory's basename where appropriate); `copy_tree` and
+        // `copy_single_file` must treat it as literal rather than
+        // re-deriving it from a second `exists()`/`is_dir()` check,
+        // which would double-join the basename whenever the resolved
+        // path already exists (the ordinary case of refreshing a
+        // previous copy).
+        source_opts.no_target_directory = true;
+
+        if source.is_dir() {
+            copy_tree(source, &source_opts, &pb, &total_bytes, &copied_bytes)?;
+        } else {
+            copy_single_file(&source, &source_opts, &pb, &total_bytes, &copied_bytes)?;
+        }
+    }
+
+    pb.end();
 
     Ok(())
 }
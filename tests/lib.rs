@@ -3,6 +3,7 @@ use failure::Error;
 use escargot::CargoBuild;
 use std::fs::{create_dir_all, File};
 use std::io::{Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
 use tempfile::tempdir;
@@ -265,5 +266,611 @@ fn copy_dirs_files() -> Result<(), Error> {
     assert!(dest_base.join("mydir/one/two/two.txt").is_file());
     assert!(dest_base.join("mydir/one/two/three/three.txt").is_file());
 
+    Ok(())
+}
+
+#[test]
+fn file_copy_atomic() -> Result<(), Error> {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text)?;
+
+    let out = run(&[
+        "--atomic",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+
+    assert!(out.status.success());
+
+    let mut dest = File::open(&dest_path)?;
+    let mut buf = String::new();
+    dest.read_to_string(&mut buf)?;
+
+    assert!(buf == text);
+
+    // No stray temp files should be left behind in the destination dir.
+    let leftovers: Vec<_> = std::fs::read_dir(dir.path())?
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name())
+        .filter(|n| n.to_string_lossy().contains(".xcp-"))
+        .collect();
+    assert!(leftovers.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn file_copy_atomic_creates_missing_parent() -> Result<(), Error> {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("nested").join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text)?;
+
+    let out = run(&[
+        "--atomic",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+
+    assert!(out.status.success());
+
+    let mut dest = File::open(&dest_path)?;
+    let mut buf = String::new();
+    dest.read_to_string(&mut buf)?;
+
+    assert!(buf == text);
+
+    // No stray temp files left behind in the newly-created parent dir.
+    let leftovers: Vec<_> = std::fs::read_dir(dest_path.parent().unwrap())?
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name())
+        .filter(|n| n.to_string_lossy().contains(".xcp-"))
+        .collect();
+    assert!(leftovers.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn verify_passes_on_good_copy() -> Result<(), Error> {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text)?;
+
+    let out = run(&[
+        "--verify",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+
+    assert!(out.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn verify_detects_stale_dest() -> Result<(), Error> {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+
+    create_file(&source_path, "original")?;
+
+    let out = run(&[source_path.to_str().unwrap(), dest_path.to_str().unwrap()])?;
+    assert!(out.status.success());
+
+    // Corrupt the copy after the fact; its mtime is now newer than the
+    // source's, so --update will (correctly) decline to re-copy it and
+    // --verify should catch the resulting mismatch.
+    create_file(&dest_path, "corrupted")?;
+
+    let out = run(&[
+        "--update",
+        "--verify",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(!out.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn verify_passes_on_fresh_dir_copy() -> Result<(), Error> {
+    // `opts.dest` doesn't exist before the copy, so `copy_tree` must
+    // reuse the same target_base for verify that it used for the walk
+    // rather than re-deriving it from the now-created destination.
+    let dir = tempdir()?;
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path)?;
+    create_file(&source_path.join("one.txt"), "one")?;
+
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "-r",
+        "--verify",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+
+    assert!(out.status.success());
+    assert!(dest_base.join("mydir/one.txt").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn verify_ignores_gitignored_files() -> Result<(), Error> {
+    // A file excluded by --gitignore is legitimately absent from the
+    // destination; --verify must not flag it as a failed copy.
+    let dir = tempdir()?;
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path)?;
+    create_file(&source_path.join("keep.txt"), "keep")?;
+    create_file(&source_path.join("secret.log"), "secret")?;
+    create_file(&source_path.join(".gitignore"), "*.log\n")?;
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base)?;
+
+    let out = run(&[
+        "-r",
+        "--gitignore",
+        "--verify",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+
+    assert!(out.status.success());
+    assert!(dest_base.join("mydir/keep.txt").is_file());
+    assert!(!dest_base.join("mydir/secret.log").exists());
+
+    Ok(())
+}
+
+#[test]
+fn update_skips_newer_dest() -> Result<(), Error> {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+
+    create_file(&source_path, "old")?;
+    create_file(&dest_path, "new already here")?;
+
+    let out = run(&[
+        "--update",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+
+    assert!(out.status.success());
+
+    let mut dest = File::open(&dest_path)?;
+    let mut buf = String::new();
+    dest.read_to_string(&mut buf)?;
+    assert!(buf == "new already here");
+
+    Ok(())
+}
+
+#[test]
+fn backup_simple_before_overwrite() -> Result<(), Error> {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+
+    create_file(&source_path, "new content")?;
+    create_file(&dest_path, "old content")?;
+
+    let out = run(&[
+        "--backup=simple",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+
+    assert!(out.status.success());
+
+    let mut dest = File::open(&dest_path)?;
+    let mut buf = String::new();
+    dest.read_to_string(&mut buf)?;
+    assert!(buf == "new content");
+
+    let backup_path = dir.path().join("dest.txt~");
+    let mut backup = File::open(&backup_path)?;
+    let mut backup_buf = String::new();
+    backup.read_to_string(&mut backup_buf)?;
+    assert!(backup_buf == "old content");
+
+    Ok(())
+}
+
+#[test]
+fn backup_numbered_picks_next_free_n() -> Result<(), Error> {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+
+    create_file(&source_path, "v1")?;
+    create_file(&dest_path, "v0")?;
+    create_file(&dir.path().join("dest.txt.~1~"), "already here")?;
+
+    let out = run(&[
+        "--backup=numbered",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    let mut buf = String::new();
+    File::open(dir.path().join("dest.txt.~2~"))?.read_to_string(&mut buf)?;
+    assert!(buf == "v0");
+
+    Ok(())
+}
+
+#[test]
+fn backup_existing_continues_numbered_series_with_gap() -> Result<(), Error> {
+    // `.~1~` was since removed but `.~2~` remains; --backup=existing
+    // must still recognize the numbered series and extend it, rather
+    // than falling back to a plain `~` backup.
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+
+    create_file(&source_path, "v2")?;
+    create_file(&dest_path, "v1")?;
+    create_file(&dir.path().join("dest.txt.~2~"), "v0")?;
+
+    let out = run(&[
+        "--backup=existing",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    assert!(!dir.path().join("dest.txt~").exists());
+
+    let mut buf = String::new();
+    File::open(dir.path().join("dest.txt.~3~"))?.read_to_string(&mut buf)?;
+    assert!(buf == "v1");
+
+    Ok(())
+}
+
+#[test]
+fn backup_existing_falls_back_to_simple_without_numbered_series() -> Result<(), Error> {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+
+    create_file(&source_path, "new content")?;
+    create_file(&dest_path, "old content")?;
+
+    let out = run(&[
+        "--backup=existing",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    let mut buf = String::new();
+    File::open(dir.path().join("dest.txt~"))?.read_to_string(&mut buf)?;
+    assert!(buf == "old content");
+
+    Ok(())
+}
+
+#[test]
+fn multi_source_into_dir() -> Result<(), Error> {
+    let dir = tempdir()?;
+
+    let source_a = dir.path().join("a.txt");
+    let source_b = dir.path().join("b.txt");
+    create_file(&source_a, "a")?;
+    create_file(&source_b, "b")?;
+
+    let dest_dir = dir.path().join("dest");
+    create_dir_all(&dest_dir)?;
+
+    let out = run(&[
+        source_a.to_str().unwrap(),
+        source_b.to_str().unwrap(),
+        dest_dir.to_str().unwrap(),
+    ])?;
+
+    assert!(out.status.success());
+    assert!(dest_dir.join("a.txt").is_file());
+    assert!(dest_dir.join("b.txt").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn target_directory_repeat_copy_of_dir_source_is_stable() -> Result<(), Error> {
+    // Re-running a `-t DIR` copy of a directory source is the ordinary
+    // way to refresh an existing tree; the resolved destination already
+    // exists on the second run, and it must still land at
+    // `DIR/mydir/...`, not get the basename joined a second time into
+    // `DIR/mydir/mydir/...`.
+    let dir = tempdir()?;
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path)?;
+    create_file(&source_path.join("one.txt"), "one")?;
+
+    let target_dir = dir.path().join("target");
+    create_dir_all(&target_dir)?;
+
+    for _ in 0..2 {
+        let out = run(&[
+            "-r",
+            "--target-directory",
+            target_dir.to_str().unwrap(),
+            source_path.to_str().unwrap(),
+        ])?;
+        assert!(out.status.success());
+    }
+
+    assert!(target_dir.join("mydir/one.txt").is_file());
+    assert!(!target_dir.join("mydir/mydir").exists());
+
+    Ok(())
+}
+
+#[test]
+fn multi_source_requires_dir_dest() -> Result<(), Error> {
+    let dir = tempdir()?;
+
+    let source_a = dir.path().join("a.txt");
+    let source_b = dir.path().join("b.txt");
+    create_file(&source_a, "a")?;
+    create_file(&source_b, "b")?;
+
+    let dest_file = dir.path().join("notadir");
+
+    let out = run(&[
+        source_a.to_str().unwrap(),
+        source_b.to_str().unwrap(),
+        dest_file.to_str().unwrap(),
+    ])?;
+
+    assert!(!out.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn no_target_directory_renames_onto_existing_dir() -> Result<(), Error> {
+    // `-T` must treat DEST as the literal final path even when it
+    // already exists as a directory, instead of copying SOURCE inside
+    // it as the plain directory-destination case would.
+    let dir = tempdir()?;
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path)?;
+    create_file(&source_path.join("one.txt"), "one")?;
+
+    let dest_path = dir.path().join("destdir");
+    create_dir_all(&dest_path)?;
+
+    let out = run(&[
+        "-r",
+        "-T",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+
+    assert!(out.status.success());
+    assert!(dest_path.join("one.txt").is_file());
+    assert!(!dest_path.join("mydir").exists());
+
+    Ok(())
+}
+
+#[test]
+fn copy_nested_gitignore() -> Result<(), Error> {
+    let dir = tempdir()?;
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path)?;
+    create_file(&source_path.join("keep.txt"), "keep")?;
+
+    let sub_path = source_path.join("sub");
+    create_dir_all(&sub_path)?;
+    create_file(&sub_path.join("keep.txt"), "keep")?;
+    create_file(&sub_path.join("secret.log"), "secret")?;
+    create_file(&sub_path.join(".gitignore"), "*.log\n")?;
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base)?;
+
+    let out = run(&[
+        "-r",
+        "--gitignore",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+
+    assert!(out.status.success());
+
+    assert!(dest_base.join("mydir/keep.txt").is_file());
+    assert!(dest_base.join("mydir/sub/keep.txt").is_file());
+    assert!(!dest_base.join("mydir/sub/secret.log").exists());
+
+    Ok(())
+}
+
+#[test]
+fn copy_root_gitignore_excludes_top_level_file() -> Result<(), Error> {
+    // The root `.gitignore` is seeded into the stack before the walk
+    // visits the source directory itself; it must still apply to that
+    // very first entry, not just to nested subdirectories.
+    let dir = tempdir()?;
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path)?;
+    create_file(&source_path.join("keep.txt"), "keep")?;
+    create_file(&source_path.join("secret.log"), "secret")?;
+    create_file(&source_path.join(".gitignore"), "*.log\n")?;
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base)?;
+
+    let out = run(&[
+        "-r",
+        "--gitignore",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+
+    assert!(out.status.success());
+
+    assert!(dest_base.join("mydir/keep.txt").is_file());
+    assert!(!dest_base.join("mydir/secret.log").exists());
+
+    Ok(())
+}
+
+#[test]
+fn copy_fifo() -> Result<(), Error> {
+    let dir = tempdir()?;
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path)?;
+    let fifo_path = source_path.join("myfifo");
+    let cpath = std::ffi::CString::new(fifo_path.to_str().unwrap())?;
+    assert!(unsafe { libc::mkfifo(cpath.as_ptr(), 0o644) } == 0);
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base)?;
+
+    let out = run(&[
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+
+    assert!(out.status.success());
+
+    let dest_fifo = dest_base.join("mydir/myfifo");
+    assert!(dest_fifo.symlink_metadata()?.file_type().is_fifo());
+
+    Ok(())
+}
+
+#[test]
+fn copy_hardlinks() -> Result<(), Error> {
+    let dir = tempdir()?;
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path)?;
+    create_file(&source_path.join("one.txt"), "linked")?;
+    std::fs::hard_link(source_path.join("one.txt"), source_path.join("two.txt"))?;
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base)?;
+
+    let out = run(&[
+        "-r",
+        "--preserve-links",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+
+    assert!(out.status.success());
+
+    let one = dest_base.join("mydir/one.txt").metadata()?;
+    let two = dest_base.join("mydir/two.txt").metadata()?;
+    assert!(one.ino() == two.ino());
+
+    Ok(())
+}
+
+#[test]
+fn copy_hardlinks_with_many_workers() -> Result<(), Error> {
+    // With multiple workers, the `Operation::Hardlink` for a later path
+    // can be dequeued by a different worker than the one still writing
+    // the original `Operation::Copy`; this exercises that race.
+    let dir = tempdir()?;
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path)?;
+    create_file(&source_path.join("one.txt"), "linked")?;
+    for n in 0..8 {
+        std::fs::hard_link(
+            source_path.join("one.txt"),
+            source_path.join(format!("link{}.txt", n)),
+        )?;
+    }
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base)?;
+
+    let out = run(&[
+        "-r",
+        "--preserve-links",
+        "--workers",
+        "8",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+
+    assert!(out.status.success());
+
+    let one = dest_base.join("mydir/one.txt").metadata()?;
+    for n in 0..8 {
+        let link = dest_base
+            .join("mydir")
+            .join(format!("link{}.txt", n))
+            .metadata()?;
+        assert!(one.ino() == link.ino());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn copy_dirs_files_with_workers() -> Result<(), Error> {
+    let dir = tempdir()?;
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path)?;
+
+    let mut p = source_path.clone();
+    for d in ["one", "two", "three"].iter() {
+        p.push(d);
+        create_dir_all(&p)?;
+        create_file(&p.join(format!("{}.txt", d)), d)?;
+    }
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base)?;
+
+    let out = run(&[
+        "-r",
+        "--workers",
+        "4",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+
+    assert!(out.status.success());
+
+    assert!(dest_base.join("mydir/one/one.txt").is_file());
+    assert!(dest_base.join("mydir/one/two/two.txt").is_file());
+    assert!(dest_base.join("mydir/one/two/three/three.txt").is_file());
+
     Ok(())
 }
\ No newline at end of file